@@ -42,22 +42,36 @@
 
 #[macro_export]
 macro_rules! scoped_mut_thread_local {
-    (static $name:ident: $ty:ty) => (
-        static $name: $crate::ScopedMutKey<$ty> = $crate::ScopedMutKey {
+    () => {};
+
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty; $($rest:tt)*) => (
+        $(#[$attr])*
+        $vis static $name: $crate::ScopedMutKey<$ty> = $crate::ScopedMutKey {
             inner: {
-                thread_local!(static FOO: ::std::cell::Cell<usize> = {
-                    ::std::cell::Cell::new(0)
+                thread_local!(static FOO: ::std::cell::Cell<*mut ()> = {
+                    ::std::cell::Cell::new(::std::ptr::null_mut())
                 });
                 &FOO
             },
             _marker: ::std::marker::PhantomData,
         };
-    )
+
+        $crate::scoped_mut_thread_local!($($rest)*);
+    );
+
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty) => (
+        $crate::scoped_mut_thread_local!($(#[$attr])* $vis static $name: $ty;);
+    );
 }
 
 use std::cell::Cell;
+use std::error::Error;
 use std::fmt;
+use std::future::Future;
 use std::marker;
+use std::pin::Pin;
+use std::ptr;
+use std::task::{Context, Poll};
 use std::thread::LocalKey;
 
 /// Type representing a thread local storage key corresponding to a mutable reference to the type
@@ -68,7 +82,7 @@ use std::thread::LocalKey;
 /// closures to control the scope of their contents.
 pub struct ScopedMutKey<T> {
     #[doc(hidden)]
-    pub inner: &'static LocalKey<Cell<usize>>,
+    pub inner: &'static LocalKey<Cell<*mut ()>>,
     #[doc(hidden)]
     pub _marker: marker::PhantomData<T>,
 }
@@ -76,8 +90,8 @@ pub struct ScopedMutKey<T> {
 unsafe impl<T> Sync for ScopedMutKey<T> {}
 
 struct Reset<'a> {
-    cell: &'a Cell<usize>,
-    val: usize,
+    cell: &'a Cell<*mut ()>,
+    val: *mut (),
 }
 
 impl<'a> Drop for Reset<'a> {
@@ -130,7 +144,7 @@ impl<T> ScopedMutKey<T> {
     {
         self.inner.with(|cell| {
             let prev = cell.get();
-            cell.set(t as *mut _ as usize);
+            cell.set(t as *mut T as *mut ());
 
             let _reset = Reset {
                 cell: cell,
@@ -141,6 +155,90 @@ impl<T> ScopedMutKey<T> {
         })
     }
 
+    /// Inserts a value into this scoped thread local storage slot and immediately hands it to a
+    /// closure, in a single thread local storage access.
+    ///
+    /// This is equivalent to calling `set` followed by `with`, except it only performs one
+    /// `LocalKey::with` lookup and installs the reentrancy guard once, rather than once for each
+    /// of `set` and `with`. Prefer this over `set`+`with` on hot paths that need the value during
+    /// the scope.
+    ///
+    /// Upon return, this function will restore the previous value, if any was available.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_mut_tls;
+    ///
+    /// scoped_mut_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// let mut num = 1;
+    ///
+    /// FOO.enter(&mut num, |slot| {
+    ///     assert_eq!(*slot, 1);
+    ///     *slot = 2;
+    /// });
+    ///
+    /// assert_eq!(num, 2);
+    /// # }
+    /// ```
+    pub fn enter<F, R>(&'static self, t: &mut T, f: F) -> R
+        where F: FnOnce(&mut T) -> R
+    {
+        self.inner.with(|cell| {
+            let prev = cell.get();
+
+            let _reset = Reset {
+                cell: cell,
+                val: prev,
+            };
+
+            // Zero the cell for the duration of `f`, just like `try_with`/`with` do, so that a
+            // reentrant `with`/`try_with` call reachable from `f` cannot observe `t` and hand out
+            // a second, simultaneously live `&mut T` aliasing the one `f` already holds.
+            cell.set(ptr::null_mut());
+
+            f(t)
+        })
+    }
+
+    /// Scopes a value for the lifetime of a future, carrying it across any `.await` points.
+    ///
+    /// Unlike `set`, which only keeps the value installed while a synchronous closure runs, this
+    /// installs the value each time the returned future is polled, and removes it again as soon
+    /// as that poll returns. This makes it possible to `with`/`try_with` the value from anywhere
+    /// inside `fut`, including after it has yielded and been resumed by an executor.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// #[macro_use]
+    /// extern crate scoped_mut_tls;
+    ///
+    /// scoped_mut_thread_local!(static FOO: u32);
+    ///
+    /// async fn run() {
+    ///     let mut num = 1;
+    ///
+    ///     FOO.scope(&mut num, async {
+    ///         other_async_fn().await;
+    ///         FOO.with(|v| *v += 1);
+    ///     }).await;
+    /// }
+    /// ```
+    pub fn scope<'a, Fut>(&'static self, value: &'a mut T, fut: Fut) -> TaskLocalFuture<'a, T, Fut>
+        where Fut: Future
+    {
+        TaskLocalFuture {
+            key: self,
+            slot: value as *mut T,
+            fut,
+            _marker: marker::PhantomData,
+        }
+    }
+
     /// Gets a value out of this scoped variable.
     ///
     /// This function takes a closure which receives the value of this variable.
@@ -166,13 +264,43 @@ impl<T> ScopedMutKey<T> {
     /// ```
     pub fn with<F, R>(&'static self, f: F) -> R
         where F: FnOnce(&mut T) -> R
+    {
+        self.try_with(f).expect("cannot access a scoped thread local \
+                                  variable without calling `set` first")
+    }
+
+    /// Gets a value out of this scoped variable, returning an error instead of panicking if the
+    /// variable is not set or is currently borrowed.
+    ///
+    /// This function takes a closure which receives the value of this variable.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// #[macro_use]
+    /// extern crate scoped_mut_tls;
+    ///
+    /// scoped_mut_thread_local!(static FOO: u32);
+    ///
+    /// # fn main() {
+    /// assert!(FOO.try_with(|_| ()).is_err());
+    ///
+    /// FOO.set(&mut 1, || {
+    ///     let val = FOO.try_with(|v| *v).unwrap();
+    ///     assert_eq!(val, 1);
+    /// });
+    /// # }
+    /// ```
+    pub fn try_with<F, R>(&'static self, f: F) -> Result<R, AccessError>
+        where F: FnOnce(&mut T) -> R
     {
         self.inner.with(|cell| {
             let val = cell.get();
-            cell.set(0);
+            cell.set(ptr::null_mut());
 
-            assert!(val != 0, "cannot access a scoped thread local \
-                               variable without calling `set` first");
+            if val.is_null() {
+                return Err(AccessError { _private: () });
+            }
 
             let _reset = Reset {
                 cell: cell,
@@ -180,14 +308,14 @@ impl<T> ScopedMutKey<T> {
             };
 
             unsafe {
-                f(&mut *(val as *mut T))
+                Ok(f(&mut *val.cast::<T>()))
             }
         })
     }
 
     /// Test whether this TLS key has been `set` for the current thread.
     pub fn is_set(&'static self) -> bool {
-        self.inner.with(|c| c.get() != 0)
+        self.inner.with(|c| !c.get().is_null())
     }
 }
 
@@ -198,14 +326,106 @@ impl<T: fmt::Debug> fmt::Debug for ScopedMutKey<T> {
     }
 }
 
+/// An error returned by [`ScopedMutKey::try_with`](struct.ScopedMutKey.html#method.try_with) when
+/// the scoped thread local variable is not set or is currently borrowed.
+#[derive(Debug)]
+pub struct AccessError {
+    _private: (),
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("scoped thread local variable not set or already borrowed")
+    }
+}
+
+impl Error for AccessError {}
+
+/// A future returned by [`ScopedMutKey::scope`](struct.ScopedMutKey.html#method.scope) that
+/// carries a scoped value for the lifetime of the wrapped future.
+///
+/// The value is installed into thread local storage for the duration of each poll of the inner
+/// future, and removed again as soon as that poll returns, so it is safe for the value to sit
+/// across an executor switching which thread polls this future from.
+pub struct TaskLocalFuture<'a, T: 'static, Fut> {
+    key: &'static ScopedMutKey<T>,
+    slot: *mut T,
+    fut: Fut,
+    _marker: marker::PhantomData<&'a mut T>,
+}
+
+// Safety: `slot` is only ever dereferenced on whatever thread is actively polling `self`, under
+// the same `Reset` guard the synchronous `set`/`with` path uses, so it carries no more than a
+// `&mut T` across threads. `T: Send` is therefore sufficient, same as `Fut: Send`.
+unsafe impl<'a, T: Send, Fut: Send> Send for TaskLocalFuture<'a, T, Fut> {}
+
+impl<'a, T, Fut> Future for TaskLocalFuture<'a, T, Fut>
+    where Fut: Future
+{
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: `fut` is never moved out of `self`, so it is sound to project a pinned
+        // reference to it from our own pinned reference.
+        let this = unsafe { self.get_unchecked_mut() };
+        let fut = unsafe { Pin::new_unchecked(&mut this.fut) };
+        let slot = this.slot;
+
+        this.key.inner.with(|cell| {
+            let prev = cell.get();
+            cell.set(slot as *mut ());
+
+            let _reset = Reset {
+                cell: cell,
+                val: prev,
+            };
+
+            fut.poll(cx)
+        })
+    }
+}
+
+impl<'a, T, Fut> fmt::Debug for TaskLocalFuture<'a, T, Fut> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("TaskLocalFuture")
+            .finish()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::cell::Cell;
+    use std::future::Future;
+    use std::pin::Pin;
     use std::sync::mpsc::{channel, Sender};
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
     use std::thread;
 
     scoped_mut_thread_local!(static FOO: u32);
 
+    fn noop_waker() -> Waker {
+        fn clone(_: *const ()) -> RawWaker { raw() }
+        fn noop(_: *const ()) {}
+        fn raw() -> RawWaker {
+            RawWaker::new(::std::ptr::null(), &VTABLE)
+        }
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+
+        unsafe { Waker::from_raw(raw()) }
+    }
+
+    fn block_on<F: Future>(mut fut: F) -> F::Output {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = unsafe { Pin::new_unchecked(&mut fut) };
+
+        loop {
+            if let Poll::Ready(val) = fut.as_mut().poll(&mut cx) {
+                return val;
+            }
+        }
+    }
+
     #[test]
     fn smoke() {
         scoped_mut_thread_local!(static BAR: u32);
@@ -220,6 +440,109 @@ mod tests {
         assert!(!BAR.is_set());
     }
 
+    #[test]
+    fn enter_fuses_set_and_with() {
+        scoped_mut_thread_local!(static BAR: u32);
+
+        let mut num = 1;
+        BAR.enter(&mut num, |slot| {
+            assert_eq!(*slot, 1);
+            *slot = 2;
+        });
+
+        assert!(!BAR.is_set());
+        assert_eq!(num, 2);
+    }
+
+    #[test]
+    fn enter_guards_against_reentrant_aliasing() {
+        scoped_mut_thread_local!(static BAR: u32);
+
+        let mut num = 1;
+        BAR.enter(&mut num, |slot| {
+            // The cell is zeroed for the duration of `f`, exactly as it is for `with`'s closure,
+            // so a reentrant access from inside `f` must not be able to alias `slot`.
+            assert!(!BAR.is_set());
+            assert!(BAR.try_with(|_| ()).is_err());
+
+            *slot += 1;
+        });
+
+        assert_eq!(num, 2);
+    }
+
+    #[test]
+    fn try_with_errors_when_unset() {
+        scoped_mut_thread_local!(static BAR: u32);
+
+        assert!(BAR.try_with(|_| ()).is_err());
+        BAR.set(&mut 1, || {
+            assert_eq!(BAR.try_with(|v| *v).unwrap(), 1);
+        });
+        assert!(BAR.try_with(|_| ()).is_err());
+    }
+
+    #[test]
+    fn multiple_declarations_and_visibility() {
+        scoped_mut_thread_local! {
+            /// a doc comment is a valid attribute
+            pub static BAZ: u32;
+            static QUUX: u32;
+        }
+
+        BAZ.set(&mut 1, || {
+            QUUX.set(&mut 2, || {
+                assert_eq!(BAZ.with(|v| *v), 1);
+                assert_eq!(QUUX.with(|v| *v), 2);
+            });
+        });
+    }
+
+    #[test]
+    fn scope_carries_across_await_points() {
+        scoped_mut_thread_local!(static BAR: u32);
+
+        async fn yield_once() {
+            let mut done = false;
+            std::future::poll_fn(move |_| {
+                if done {
+                    Poll::Ready(())
+                } else {
+                    done = true;
+                    Poll::Pending
+                }
+            }).await
+        }
+
+        let mut num = 1;
+        block_on(BAR.scope(&mut num, async {
+            assert!(BAR.is_set());
+            BAR.with(|v| *v += 1);
+            yield_once().await;
+            assert!(BAR.is_set());
+            BAR.with(|v| *v += 1);
+        }));
+
+        assert!(!BAR.is_set());
+        assert_eq!(num, 3);
+    }
+
+    #[test]
+    fn scope_future_is_send_when_inner_future_and_value_are() {
+        fn assert_send<T: Send>(_: &T) {}
+
+        scoped_mut_thread_local!(static BAR: u32);
+
+        let mut num = 1;
+        let fut = BAR.scope(&mut num, async {
+            BAR.with(|v| *v += 1);
+        });
+
+        assert_send(&fut);
+        block_on(fut);
+        assert_eq!(num, 2);
+    }
+
     #[test]
     fn cell_allowed() {
         scoped_mut_thread_local!(static BAR: Cell<u32>);